@@ -2,7 +2,7 @@ use std::time::{Instant};
 use std::fs;
 use std::str;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
     And,
     Goto,
@@ -53,6 +53,7 @@ pub enum TokenType {
     Minus,
     Mul,
     Div,
+    IDiv, // //
     Mod,
     Pow,
     Len,
@@ -110,20 +111,112 @@ impl TokenType {
     }
 }
 
-#[derive(Debug)]
-enum TokenValue {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right
+}
+
+// Maps each binary operator token to its Lua precedence level and
+// associativity in one place. Any variant not listed falls through to
+// `None`, so a new operator variant has no binding power until it is
+// added here.
+macro_rules! binary_precedence {
+    ($self:expr, { $($pattern:pat => ($prec:expr, $assoc:expr)),* $(,)? }) => {
+        match $self {
+            $($pattern => Some(($prec, $assoc)),)*
+            _ => None
+        }
+    };
+}
+
+impl TokenType {
+    // Binary operator precedence, low to high, per the Lua manual:
+    // or < and < comparisons < | < ~ < & < shifts < .. (right) <
+    // +/- < *// // % < unary operators < ^ (right). Unary operators have
+    // no binary precedence of their own; see `is_unary_op`.
+    pub fn precedence(&self) -> Option<(u8, Assoc)> {
+        binary_precedence!(self, {
+            TokenType::Or => (1, Assoc::Left),
+            TokenType::And => (2, Assoc::Left),
+            TokenType::Less
+                | TokenType::Greater
+                | TokenType::LessOrEqual
+                | TokenType::GreaterOrEqual
+                | TokenType::NotEqual
+                | TokenType::Equal => (3, Assoc::Left),
+            TokenType::BOr => (4, Assoc::Left),
+            TokenType::BXor => (5, Assoc::Left),
+            TokenType::BAnd => (6, Assoc::Left),
+            TokenType::ShiftLeft | TokenType::ShiftRight => (7, Assoc::Left),
+            TokenType::Concat => (8, Assoc::Right),
+            TokenType::Add | TokenType::Minus => (9, Assoc::Left),
+            TokenType::Mul | TokenType::Div | TokenType::IDiv | TokenType::Mod => (10, Assoc::Left),
+            TokenType::Pow => (12, Assoc::Right),
+        })
+    }
+
+    pub fn is_binary_op(&self) -> bool {
+        self.precedence().is_some()
+    }
+
+    // `not`, `#`, unary `-` and unary `~` bind tighter than any binary
+    // operator except `^`, which binds tighter still.
+    pub fn is_unary_op(&self) -> bool {
+        matches!(self, TokenType::Not | TokenType::Len | TokenType::Minus | TokenType::BXor)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenValue {
     None,
     Int(i64),
     Float(f64),
-    Str(String)
+    Str(String),
+    // Lua strings are byte sequences, not necessarily valid UTF-8: `\xHH`
+    // and `\ddd` escapes can decode to any byte 0-255. Used for
+    // `StringLiteral` instead of `Str` so a value like `"\xFF"` doesn't
+    // have to be (and can't be) forced into a `String`.
+    Bytes(Vec<u8>)
+}
+
+type LexOutput = (TokenType, TokenValue, Option<LexErrorKind>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LexErrorKind {
+    UnterminatedString,
+    UnterminatedLongComment,
+    MalformedNumber,
+    InvalidEscape,
+    UnexpectedByte(u8)
 }
 
-type LexResult = Result<(TokenType, TokenValue), ()>;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub col: u32
+}
 
-struct Tokenizer<'a> {
-    input: &'a String,
+pub struct Tokenizer<'a> {
+    input: &'a str,
     pos: usize,
-    tokens: Vec<(TokenType, TokenValue)>
+    line: u32,
+    col: u32,
+    done: bool,
+    errors: Vec<(Span, LexErrorKind)>
+}
+
+pub fn tokenize(input: &str) -> Tokenizer<'_> {
+    Tokenizer {
+        input,
+        pos: 0,
+        line: 1,
+        col: 1,
+        done: false,
+        errors: Vec::new()
+    }
 }
 
 impl<'a> Tokenizer<'a> {
@@ -147,6 +240,42 @@ impl<'a> Tokenizer<'a> {
         !self.has_at_least(0)
     }
 
+    pub fn errors(&self) -> Vec<(Span, LexErrorKind)> {
+        self.errors.clone()
+    }
+
+    // Advances pos by one byte, keeping line/col in sync. Continuation bytes
+    // of a multi-byte UTF-8 sequence don't bump col, so col tracks characters
+    // rather than bytes.
+    fn advance(&mut self) {
+        let byte = self.byte_at(0);
+        self.pos += 1;
+
+        if byte == b'\n' {
+            self.line += 1;
+            self.col = 1;
+        } else if byte & 0xC0 != 0x80 {
+            self.col += 1;
+        }
+    }
+
+    fn advance_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.advance();
+        }
+    }
+
+    // Decodes the character starting at `self.pos + offset` along with its
+    // UTF-8 length, so identifier scanning can advance by characters rather
+    // than individual bytes. Returns a NUL char past EOF or at a non-char
+    // boundary rather than panicking.
+    fn char_at(&self, offset: usize) -> (char, usize) {
+        match self.input.get(self.pos + offset..).and_then(|s| s.chars().next()) {
+            Some(c) => (c, c.len_utf8()),
+            None => ('\0', 0)
+        }
+    }
+
     fn is_whitespace(&self) -> bool {
         match self.byte_at(0) {
             b' ' | b'\t' | b'\x0B' | b'\x0C' => true,
@@ -156,7 +285,7 @@ impl<'a> Tokenizer<'a> {
 
     fn is_escape_char(&self) -> bool {
         match self.byte_at(0) {
-            b'\x41' | b'\n' | b'\r' | b'\t' | b'\\' | b'\0' | b'\x7F' => true,
+            b'\n' | b'\r' | b'\t' | b'\\' | b'\0' | b'\x7F' => true,
             _ => false
         }
     }
@@ -178,37 +307,31 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    fn is_alpha(&self) -> bool {
-        match self.byte_at(0) {
-            b'a' ..=b'z' | b'A' ..=b'Z' => {
-                true
-            },
-            _ => false
-        }
-    }
-
+    // Lua identifiers follow Unicode XID_Start/XID_Continue (the same rule
+    // proc-macro2 applies via the unicode_xid crate). This binary has no
+    // external dependencies, so `char::is_alphabetic`/`is_alphanumeric`
+    // stand in for the formal XID properties.
     fn is_valid_ident_start(&self) -> bool {
-        self.is_alpha() || self.byte_at(0) == b'_'
+        let (c, _) = self.char_at(0);
+        c == '_' || c.is_alphabetic()
     }
 
     fn is_valid_ident(&self) -> bool {
-        self.is_alpha() || Tokenizer::is_digit(self.byte_at(0)) || self.is_valid_ident_start()
+        let (c, _) = self.char_at(0);
+        c == '_' || c.is_alphanumeric()
     }
 
-    fn next(&mut self) -> LexResult {
-        next_token(self)
-    }
 
     #[allow(irrefutable_let_patterns)]
-    fn read_single_line_comment(&mut self) -> LexResult {
+    fn read_single_line_comment(&mut self) -> LexOutput {
         let mut comment: Vec<u8> = Vec::new();
 
         loop {
             if self.byte_at(0) != b'\n' {
                 comment.push(self.byte_at(0));
-                self.pos += 1;
+                self.advance();
             } else if self.byte_at(0) == b'\n' {
-                self.pos += 1;
+                self.advance();
                 break;
             } else {
                 break;
@@ -216,74 +339,241 @@ impl<'a> Tokenizer<'a> {
         }
 
         if let comment = str::from_utf8(&comment) {
-            Ok((TokenType::SingleComment, TokenValue::Str(comment.unwrap().to_string()))) // TODO: add real token value
+            (TokenType::SingleComment, TokenValue::Str(comment.unwrap().to_string()), None) // TODO: add real token value
         } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
+            (TokenType::Unidentified, TokenValue::None, Some(LexErrorKind::UnexpectedByte(self.byte_at(0))))
         }
     }
 
-    #[allow(irrefutable_let_patterns)]
-    fn read_multi_line_comment(&mut self) -> LexResult {
-        let mut comment: Vec<u8> = Vec::new();
+    // A long bracket opener is `[`, `n` `=` characters, then `[`; returns the
+    // level `n` when the byte at `offset` and everything after it matches,
+    // regardless of how many `=`s are in between. Checks the first `[`
+    // itself rather than assuming the caller already matched it, since a
+    // run of `=`s followed by `[` can appear after arbitrary other bytes
+    // (e.g. partway through a single-line comment).
+    fn long_bracket_level(&self, offset: usize) -> Option<usize> {
+        if self.byte_at(offset) != b'[' {
+            return None;
+        }
+
+        let mut i = offset + 1;
+        let mut level = 0;
+
+        while self.byte_at(i) == b'=' {
+            level += 1;
+            i += 1;
+        }
+
+        if self.byte_at(i) == b'[' {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    // A long bracket is closed only by `]`, the same number of `=`s as its
+    // opener, then `]`.
+    fn is_closing_long_bracket(&self, level: usize) -> bool {
+        if self.byte_at(0) != b']' {
+            return false;
+        }
+
+        for i in 0..level {
+            if self.byte_at(1 + i) != b'=' {
+                return false;
+            }
+        }
+
+        self.byte_at(1 + level) == b']'
+    }
+
+    // Reads the body of a `[=*[ ... ]=*]` long bracket, used for both
+    // long-string literals and long comments. The caller has already
+    // consumed the opening bracket and decides which `kind` of token this
+    // produces.
+    fn read_long_bracket(&mut self, level: usize, kind: TokenType) -> LexOutput {
+        let mut content: Vec<u8> = Vec::new();
+
+        if self.byte_at(0) == b'\r' && self.byte_at(1) == b'\n' {
+            self.advance_n(2);
+        } else if self.byte_at(0) == b'\n' {
+            self.advance();
+        }
 
         loop {
-            if self.is_escape_char() {
-                self.pos += 1;
-            } else if self.starts_with(b"]]") {
-                self.pos += 2;
+            if self.is_eof() {
+                let error = if kind == TokenType::StringLiteral {
+                    LexErrorKind::UnterminatedString
+                } else {
+                    LexErrorKind::UnterminatedLongComment
+                };
+
+                return (kind, TokenValue::None, Some(error));
+            } else if self.is_closing_long_bracket(level) {
+                self.advance_n(level + 2);
                 break;
             } else {
-                comment.push(self.byte_at(0));
-                self.pos += 1;
+                content.push(self.byte_at(0));
+                self.advance();
             }
         }
 
-        if let comment = str::from_utf8(&comment) {
-            Ok((TokenType::MultiLineComment, TokenValue::Str(comment.unwrap().to_string())))
+        // A long string is a byte sequence like any other string literal; a
+        // long comment's text is only ever used for display, so it's kept
+        // as a `String` (always valid UTF-8, since it's sliced out of the
+        // source `&str` verbatim with no escape processing).
+        if kind == TokenType::StringLiteral {
+            return (kind, TokenValue::Bytes(content), None);
+        }
+
+        if let Ok(s) = str::from_utf8(&content) {
+            (kind, TokenValue::Str(s.to_string()), None)
         } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
+            (TokenType::Unidentified, TokenValue::None, Some(LexErrorKind::UnexpectedByte(self.byte_at(0))))
         }
     }
 
-    #[allow(irrefutable_let_patterns)]
-    fn read_string(&mut self) -> LexResult {
+    fn read_string(&mut self) -> LexOutput {
         let mut string: Vec<u8> = Vec::new();
         let mut is_closed = false;
+        let mut error = None;
 
         let start_char = self.byte_at(0);
-        self.pos += 1;
+        self.advance();
 
         loop {
             if self.byte_at(0) == b'\\' {
-                string.push(self.byte_at(0));
-                string.push(self.byte_at(1));
-                self.pos += 2;
-            }
-            if self.byte_at(0) != start_char && self.byte_at(0) != 3 {
-                string.push(self.byte_at(0));
-                self.pos += 1;
+                let (decoded, escape_error) = self.read_escape_sequence();
+                string.extend(decoded);
+
+                if escape_error.is_some() {
+                    error = escape_error;
+                }
             } else if self.byte_at(0) == start_char {
                 is_closed = true;
-                self.pos += 1;
+                self.advance();
                 break;
-            } else {
+            } else if self.byte_at(0) == 3 {
                 break;
+            } else {
+                string.push(self.byte_at(0));
+                self.advance();
             }
         }
 
-        if let string = str::from_utf8(&string) {
-            if is_closed {
-                Ok((TokenType::StringLiteral, TokenValue::Str(string.unwrap().to_string())))
-            } else {
-                Ok((TokenType::UnclosedStringLiteral, TokenValue::None))
+        if !is_closed {
+            return (TokenType::UnclosedStringLiteral, TokenValue::None, Some(LexErrorKind::UnterminatedString));
+        }
+
+        // The decoded bytes aren't necessarily valid UTF-8 (e.g. `\xFF`), so
+        // they're carried as raw bytes rather than forced into a `String`.
+        (TokenType::StringLiteral, TokenValue::Bytes(string), error)
+    }
+
+    // Consumes a `\` escape inside a string literal and returns the bytes it
+    // decodes to. Unrecognized escapes are dropped (not copied through) and
+    // flagged via `LexErrorKind::InvalidEscape`.
+    fn read_escape_sequence(&mut self) -> (Vec<u8>, Option<LexErrorKind>) {
+        self.advance();
+
+        match self.byte_at(0) {
+            b'a' => { self.advance(); (vec![0x07], None) },
+            b'b' => { self.advance(); (vec![0x08], None) },
+            b'f' => { self.advance(); (vec![0x0C], None) },
+            b'n' => { self.advance(); (vec![b'\n'], None) },
+            b'r' => { self.advance(); (vec![b'\r'], None) },
+            b't' => { self.advance(); (vec![b'\t'], None) },
+            b'v' => { self.advance(); (vec![0x0B], None) },
+            b'\\' => { self.advance(); (vec![b'\\'], None) },
+            b'\"' => { self.advance(); (vec![b'\"'], None) },
+            b'\'' => { self.advance(); (vec![b'\''], None) },
+            b'\n' => { self.advance(); (vec![b'\n'], None) },
+            b'\r' => {
+                self.advance();
+                if self.byte_at(0) == b'\n' {
+                    self.advance();
+                }
+                (vec![b'\n'], None)
+            },
+            b'z' => {
+                self.advance();
+                while self.is_whitespace() || self.byte_at(0) == b'\n' || self.byte_at(0) == b'\r' {
+                    self.advance();
+                }
+                (Vec::new(), None)
+            },
+            b'x' => {
+                self.advance();
+                let mut hex: Vec<u8> = Vec::new();
+
+                for _ in 0..2 {
+                    if Tokenizer::is_hex_digit(self.byte_at(0)) {
+                        hex.push(self.byte_at(0));
+                        self.advance();
+                    }
+                }
+
+                if hex.len() == 2 {
+                    let value = u8::from_str_radix(str::from_utf8(&hex).unwrap(), 16).unwrap();
+                    (vec![value], None)
+                } else {
+                    (Vec::new(), Some(LexErrorKind::InvalidEscape))
+                }
+            },
+            b'u' if self.byte_at(1) == b'{' => {
+                self.advance_n(2);
+                let mut digits: Vec<u8> = Vec::new();
+
+                while self.byte_at(0) != b'}' && self.byte_at(0) != 3 {
+                    digits.push(self.byte_at(0));
+                    self.advance();
+                }
+
+                if self.byte_at(0) == b'}' {
+                    self.advance();
+                }
+
+                let code_point = str::from_utf8(&digits).ok()
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32);
+
+                match code_point {
+                    Some(c) => {
+                        let mut buf = [0u8; 4];
+                        (c.encode_utf8(&mut buf).as_bytes().to_vec(), None)
+                    },
+                    None => (Vec::new(), Some(LexErrorKind::InvalidEscape))
+                }
+            },
+            b'0'..=b'9' => {
+                let mut digits: Vec<u8> = Vec::new();
+
+                for _ in 0..3 {
+                    if Tokenizer::is_digit(self.byte_at(0)) {
+                        digits.push(self.byte_at(0));
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+
+                let value = str::from_utf8(&digits).unwrap().parse::<u32>().unwrap();
+
+                if value <= 255 {
+                    (vec![value as u8], None)
+                } else {
+                    (Vec::new(), Some(LexErrorKind::InvalidEscape))
+                }
+            },
+            _ => {
+                self.advance();
+                (Vec::new(), Some(LexErrorKind::InvalidEscape))
             }
-        } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
         }
     }
 
     #[allow(irrefutable_let_patterns)]
-    fn read_digit(&mut self) -> LexResult {
+    fn read_digit(&mut self) -> LexOutput {
         let mut num_str: Vec<u8> = Vec::new();
         let mut hex = false;
 
@@ -291,24 +581,36 @@ impl<'a> Tokenizer<'a> {
             num_str.push(self.byte_at(0));
             num_str.push(self.byte_at(1));
 
-            self.pos += 2;
+            self.advance_n(2);
             hex = true;
         }
 
         loop {
-            if !hex && (Tokenizer::is_digit(self.byte_at(0)) || self.byte_at(0) == b'.') {
+            let is_body_digit = if hex { Tokenizer::is_hex_digit(self.byte_at(0)) } else { Tokenizer::is_digit(self.byte_at(0)) };
+
+            if is_body_digit || self.byte_at(0) == b'.' {
                 num_str.push(self.byte_at(0));
-                self.pos += 1;
-            } else if hex && Tokenizer::is_hex_digit(self.byte_at(0)) {
+                self.advance();
+            } else if !hex && (self.byte_at(0) == b'e' || self.byte_at(0) == b'E') {
                 num_str.push(self.byte_at(0));
-                self.pos += 1;
-            } else if self.byte_at(0) == b'e' || self.byte_at(0) == b'E' {
+                self.advance();
+
+                if self.byte_at(0) == b'-' || self.byte_at(0) == b'+' {
+                    num_str.push(self.byte_at(0));
+                    self.advance();
+                }
+            } else if hex && (self.byte_at(0) == b'p' || self.byte_at(0) == b'P') {
                 num_str.push(self.byte_at(0));
-                self.pos += 1;
+                self.advance();
 
                 if self.byte_at(0) == b'-' || self.byte_at(0) == b'+' {
                     num_str.push(self.byte_at(0));
-                    self.pos += 1;
+                    self.advance();
+                }
+
+                while Tokenizer::is_digit(self.byte_at(0)) {
+                    num_str.push(self.byte_at(0));
+                    self.advance();
                 }
             } else {
                 break;
@@ -316,24 +618,65 @@ impl<'a> Tokenizer<'a> {
         }
 
         if let string = str::from_utf8(&num_str) {
-            let num = self.string_to_number(string.unwrap()).unwrap();
-            match num.0 {
-                TokenType::Int => Ok((TokenType::Int, num.1)),
-                TokenType::Float => Ok((TokenType::Float, num.1)),
-                _ => Ok((TokenType::Unidentified, TokenValue::None))
+            let text = string.unwrap();
+            let is_hex_float = hex && (text.contains('.') || text.contains('p') || text.contains('P'));
+
+            let result = if is_hex_float {
+                self.string_to_hex_float(text).map(|v| (TokenType::Float, v))
+            } else {
+                self.string_to_number(text)
+            };
+
+            match result {
+                Some((token_type, value)) => (token_type, value, None),
+                None => (TokenType::Int, TokenValue::None, Some(LexErrorKind::MalformedNumber))
             }
         } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
+            (TokenType::Unidentified, TokenValue::None, Some(LexErrorKind::UnexpectedByte(self.byte_at(0))))
         }
     }
 
-    fn string_to_number(&mut self, string: &str) -> Result<(TokenType, TokenValue), ()> {
+    // Parses a Lua hex float: `0x` hex digits, an optional `.` fractional
+    // part, and an optional `p`/`P` binary exponent (a power of two, unlike
+    // the decimal `e`/`E` exponent on regular floats).
+    fn string_to_hex_float(&self, string: &str) -> Option<TokenValue> {
+        let body = &string[2..];
+
+        let (mantissa, exponent) = match body.find(['p', 'P']) {
+            Some(idx) => (&body[..idx], body[idx + 1..].parse::<i32>().ok()?),
+            None => (body, 0)
+        };
+
+        let (int_part, frac_part) = match mantissa.find('.') {
+            Some(idx) => (&mantissa[..idx], &mantissa[idx + 1..]),
+            None => (mantissa, "")
+        };
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+
+        let mut value = 0f64;
+        for c in int_part.chars() {
+            value = value * 16.0 + c.to_digit(16)? as f64;
+        }
+
+        let mut frac_scale = 1.0 / 16.0;
+        for c in frac_part.chars() {
+            value += c.to_digit(16)? as f64 * frac_scale;
+            frac_scale /= 16.0;
+        }
+
+        Some(TokenValue::Float(value * 2f64.powi(exponent)))
+    }
+
+    fn string_to_number(&mut self, string: &str) -> Option<(TokenType, TokenValue)> {
         if let Some(n) = self.string_to_int(string) {
-            Ok((TokenType::Int, n))
+            Some((TokenType::Int, n))
         } else if let Some(t) = self.string_to_float(string) {
-            Ok((TokenType::Float, t))
+            Some((TokenType::Float, t))
         } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
+            None
         }
     }
 
@@ -374,151 +717,207 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    fn read_other_tokens(&mut self) -> LexResult {
+    fn read_other_tokens(&mut self) -> LexOutput {
         let token_type = match self.byte_at(0) {
             b';' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Semicolon)
             },
             b',' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Comma)
             },
             b'&' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::BAnd)
             },
             b'|' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::BOr)
             },
             b'(' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::LeftParenthesis)
             },
             b')' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::RightParenthesis)
             },
             b']' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::RightSquareBracket)
             },
             b'{' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::LeftCurlyBracket)
             },
             b'}' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::RightCurlyBracket)
             },
             b'+' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Add)
             },
             b'*' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Mul)
             },
-            b'/' => {
-                self.pos += 1;
-                Some(TokenType::Div)
-            },
             b'%' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Mod)
             },
             b'^' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Pow)
             },
             b'#' => {
-                self.pos += 1;
+                self.advance();
                 Some(TokenType::Len)
             },
             _ => None
         };
 
         if let Some(t) = token_type {
-            Ok((t, TokenValue::None))
+            (t, TokenValue::None, None)
         } else if self.is_valid_ident_start() {
             let mut word: Vec<u8> = Vec::new();
 
-            word.push(self.byte_at(0));
-            self.pos += 1;
+            let (c, len) = self.char_at(0);
+            let mut buf = [0u8; 4];
+            word.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            self.advance_n(len);
 
             while self.is_valid_ident() {
-                word.push(self.byte_at(0));
-                self.pos += 1;
+                let (c, len) = self.char_at(0);
+                let mut buf = [0u8; 4];
+                word.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                self.advance_n(len);
             }
 
             if let Ok(s) = str::from_utf8(&word) {
                 if let Some(t) = TokenType::convert_to_token_type(s) {
-                    Ok((t, TokenValue::None))
+                    (t, TokenValue::None, None)
                 } else {
-                    Ok((TokenType::Identifier, TokenValue::Str(s.to_string())))
+                    (TokenType::Identifier, TokenValue::Str(s.to_string()), None)
                 }
             } else {
-                Ok((TokenType::Unidentified, TokenValue::None))
+                (TokenType::Unidentified, TokenValue::None, Some(LexErrorKind::UnexpectedByte(self.byte_at(0))))
             }
         } else {
-            Ok((TokenType::Unidentified, TokenValue::None))
+            let byte = self.byte_at(0);
+            self.advance();
+            (TokenType::Unidentified, TokenValue::None, Some(LexErrorKind::UnexpectedByte(byte)))
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (TokenType, TokenValue, Span, Option<LexErrorKind>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let start = self.pos;
+        let line = self.line;
+        let col = self.col;
+
+        if self.is_eof() {
+            self.done = true;
+            let span = Span { start, end: start, line, col };
+            return Some((TokenType::Eof, TokenValue::None, span, None));
+        }
+
+        let (token_type, value, error) = next_token(self);
+        let span = Span { start, end: self.pos, line, col };
+
+        if let Some(kind) = error {
+            self.errors.push((span, kind));
         }
+
+        Some((token_type, value, span, error))
     }
 }
 
-fn next_token<'a>(tokenizer: &mut Tokenizer<'a>) -> LexResult {
+fn next_token<'a>(tokenizer: &mut Tokenizer<'a>) -> LexOutput {
     match tokenizer.byte_at(0) {
         _ if tokenizer.is_whitespace() => {
-            tokenizer.pos += 1;
-            Ok((TokenType::Whitespace, TokenValue::None))
+            tokenizer.advance();
+            (TokenType::Whitespace, TokenValue::None, None)
         }
-        _ if Tokenizer::is_digit(tokenizer.byte_at(0)) => Ok(tokenizer.read_digit()?),
-        _ if tokenizer.is_escape_char() => {tokenizer.pos += 1; Ok((TokenType::Whitespace, TokenValue::None))}
+        _ if Tokenizer::is_digit(tokenizer.byte_at(0)) => tokenizer.read_digit(),
+        _ if tokenizer.is_escape_char() => {tokenizer.advance(); (TokenType::Whitespace, TokenValue::None, None)}
 
-        b'\"' | b'\'' => Ok(tokenizer.read_string()?),
+        b'\"' | b'\'' => tokenizer.read_string(),
         b'>' => {
-            if tokenizer.starts_with(b">=") {tokenizer.pos += 2; Ok((TokenType::GreaterOrEqual, TokenValue::None))}
-            else if tokenizer.starts_with(b">>") {tokenizer.pos += 2; Ok((TokenType::ShiftRight, TokenValue::None))}
-            else {tokenizer.pos += 1; Ok((TokenType::Greater, TokenValue::None))}
+            if tokenizer.starts_with(b">=") {tokenizer.advance_n(2); (TokenType::GreaterOrEqual, TokenValue::None, None)}
+            else if tokenizer.starts_with(b">>") {tokenizer.advance_n(2); (TokenType::ShiftRight, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::Greater, TokenValue::None, None)}
         },
         b'.' => {
-            if tokenizer.starts_with(b"..") {tokenizer.pos += 2; Ok((TokenType::Concat, TokenValue::None))}
-            else if tokenizer.starts_with(b"...") {tokenizer.pos += 3; Ok((TokenType::Dots, TokenValue::None))}
+            if tokenizer.starts_with(b"...") {tokenizer.advance_n(3); (TokenType::Dots, TokenValue::None, None)}
+            else if tokenizer.starts_with(b"..") {tokenizer.advance_n(2); (TokenType::Concat, TokenValue::None, None)}
             else {
                 match tokenizer.byte_at(1) {
-                    b'0'..=b'9' => {(); Ok(tokenizer.read_digit()?)},
-                    _ => {tokenizer.pos += 1; Ok((TokenType::Attr, TokenValue::None))}
+                    b'0'..=b'9' => tokenizer.read_digit(),
+                    _ => {tokenizer.advance(); (TokenType::Attr, TokenValue::None, None)}
                 }
             }
         },
         b'=' => {
-            if tokenizer.starts_with(b"==") {tokenizer.pos += 2; Ok((TokenType::Concat, TokenValue::None))}
-            else {tokenizer.pos += 1; Ok((TokenType::Assign, TokenValue::None))}
+            if tokenizer.starts_with(b"==") {tokenizer.advance_n(2); (TokenType::Equal, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::Assign, TokenValue::None, None)}
+        },
+        b'/' => {
+            if tokenizer.starts_with(b"//") {tokenizer.advance_n(2); (TokenType::IDiv, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::Div, TokenValue::None, None)}
         },
         b'<' => {
-            if tokenizer.starts_with(b"<=") {tokenizer.pos += 2; Ok((TokenType::LessOrEqual, TokenValue::None))}
-            else if tokenizer.starts_with(b"<<") {tokenizer.pos += 2; Ok((TokenType::ShiftLeft, TokenValue::None))}
-            else {tokenizer.pos += 1; Ok((TokenType::Less, TokenValue::None))}
+            // `<const>`/`<close>` attributes are not a distinct token here:
+            // comparisons chain left-to-right in Lua (`n<const>m` is legal,
+            // meaning `(n < const) > m`), so swallowing `<name>` whole would
+            // misparse any comparison against a variable named `const` or
+            // `close`. Lex `<`, the name, and `>` as ordinary tokens and
+            // leave attribute detection to the parser, which knows it's only
+            // looking for one in a `local <name>` position.
+            if tokenizer.starts_with(b"<=") {tokenizer.advance_n(2); (TokenType::LessOrEqual, TokenValue::None, None)}
+            else if tokenizer.starts_with(b"<<") {tokenizer.advance_n(2); (TokenType::ShiftLeft, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::Less, TokenValue::None, None)}
         },
         b'~' => {
-            if tokenizer.starts_with(b"~=") {tokenizer.pos += 2; Ok((TokenType::NotEqual, TokenValue::None))}
-            else {tokenizer.pos += 1; Ok((TokenType::BXor, TokenValue::None))}
+            if tokenizer.starts_with(b"~=") {tokenizer.advance_n(2); (TokenType::NotEqual, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::BXor, TokenValue::None, None)}
         },
         b':' => {
-            if tokenizer.starts_with(b"::") {tokenizer.pos += 2; Ok((TokenType::DoubleColon, TokenValue::None))}
-            else {tokenizer.pos += 1; Ok((TokenType::Colon, TokenValue::None))}
+            if tokenizer.starts_with(b"::") {tokenizer.advance_n(2); (TokenType::DoubleColon, TokenValue::None, None)}
+            else {tokenizer.advance(); (TokenType::Colon, TokenValue::None, None)}
         },
         b'[' => {
-            if tokenizer.starts_with(b"[[") {tokenizer.pos += 2; Ok(tokenizer.read_multi_line_comment()?)}
-            else {tokenizer.pos += 1; Ok((TokenType::LeftSquareBracket, TokenValue::None))}
+            if let Some(level) = tokenizer.long_bracket_level(0) {
+                tokenizer.advance_n(level + 2);
+                tokenizer.read_long_bracket(level, TokenType::StringLiteral)
+            } else {
+                tokenizer.advance();
+                (TokenType::LeftSquareBracket, TokenValue::None, None)
+            }
         },
         b'-' => {
-            if tokenizer.starts_with(b"--[[") {tokenizer.pos += 4; Ok(tokenizer.read_multi_line_comment()?)}
-            else if tokenizer.starts_with(b"--") {tokenizer.pos += 2; Ok(tokenizer.read_single_line_comment()?)}
-            else {tokenizer.pos += 1; Ok((TokenType::Minus, TokenValue::None))}
+            if tokenizer.starts_with(b"--") {
+                if let Some(level) = tokenizer.long_bracket_level(2) {
+                    tokenizer.advance_n(2 + level + 2);
+                    tokenizer.read_long_bracket(level, TokenType::MultiLineComment)
+                } else {
+                    tokenizer.advance_n(2);
+                    tokenizer.read_single_line_comment()
+                }
+            } else {
+                tokenizer.advance();
+                (TokenType::Minus, TokenValue::None, None)
+            }
         },
-        _ => Ok(tokenizer.read_other_tokens()?)
+        _ => tokenizer.read_other_tokens()
     }
 }
 
@@ -526,25 +925,384 @@ fn next_token<'a>(tokenizer: &mut Tokenizer<'a>) -> LexResult {
 fn main() {
     let input = fs::read_to_string("src/file_test.txt").unwrap();
 
-    let mut tokenizer = Tokenizer {
-        input: &input,
-        pos: 0,
-        tokens: Vec::new()
-    };
-
     let start = Instant::now();
-    while !tokenizer.is_eof() {
-        let token_type = tokenizer.next().unwrap();
-        tokenizer.tokens.push(token_type);
-    }
-    tokenizer.tokens.push((TokenType::Eof, TokenValue::None));
+    let mut token_count = 0;
+
+    let mut tokenizer = tokenize(&input);
 
-    #[cfg(debug_assertions)]
-    for token in &tokenizer.tokens {
-        println!("type: {:#?}, value: {:#?}", token.0, token.1);
+    for token in &mut tokenizer {
+        #[cfg(debug_assertions)]
+        println!("type: {:#?}, value: {:#?}, span: {:#?}, error: {:#?}", token.0, token.1, token.2, token.3);
+
+        token_count += 1;
     }
 
     let duration = start.elapsed();
-    println!("tokens: {:#?}", tokenizer.tokens.len());
+    println!("tokens: {:#?}", token_count);
+    println!("errors: {:#?}", tokenizer.errors());
     println!("duration: {:#?}", duration);
 }
+
+#[cfg(test)]
+mod long_bracket_tests {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<(TokenType, TokenValue)> {
+        tokenize(input).map(|(t, v, _, _)| (t, v)).collect()
+    }
+
+    #[test]
+    fn long_string_at_level_zero() {
+        assert_eq!(
+            kinds("[[hello]]"),
+            vec![
+                (TokenType::StringLiteral, TokenValue::Bytes(b"hello".to_vec())),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_string_at_nested_levels() {
+        assert_eq!(
+            kinds("[==[ a [[ nested ]] b ]==]"),
+            vec![
+                (TokenType::StringLiteral, TokenValue::Bytes(b" a [[ nested ]] b ".to_vec())),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_long_string_is_flagged() {
+        let mut tokenizer = tokenize("[==[ oops");
+        let tokens: Vec<_> = (&mut tokenizer).collect();
+
+        assert_eq!(tokens[0].0, TokenType::StringLiteral);
+        assert_eq!(tokenizer.errors(), vec![(tokens[0].2, LexErrorKind::UnterminatedString)]);
+    }
+
+    #[test]
+    fn long_comment_at_nested_levels() {
+        assert_eq!(
+            kinds("--[==[ nested [[ comment ]==]\nprint(1)"),
+            vec![
+                (TokenType::MultiLineComment, TokenValue::Str(" nested [[ comment ".to_string())),
+                (TokenType::Whitespace, TokenValue::None),
+                (TokenType::Identifier, TokenValue::Str("print".to_string())),
+                (TokenType::LeftParenthesis, TokenValue::None),
+                (TokenType::Int, TokenValue::Int(1)),
+                (TokenType::RightParenthesis, TokenValue::None),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_long_comment_is_flagged() {
+        let mut tokenizer = tokenize("--[[ oops");
+        let tokens: Vec<_> = (&mut tokenizer).collect();
+
+        assert_eq!(tokens[0].0, TokenType::MultiLineComment);
+        assert_eq!(tokenizer.errors(), vec![(tokens[0].2, LexErrorKind::UnterminatedLongComment)]);
+    }
+
+    // A single-line comment whose body merely contains a `[` (e.g. "[note]")
+    // without forming a `[`, `=`s, `[` long-bracket opener must stay a
+    // single-line comment rather than swallowing the rest of the file.
+    #[test]
+    fn single_line_comment_resembling_a_long_bracket_is_not_swallowed() {
+        assert_eq!(
+            kinds("-- [note] this is just a regular comment\nprint(1)"),
+            vec![
+                (TokenType::SingleComment, TokenValue::Str(" [note] this is just a regular comment".to_string())),
+                (TokenType::Identifier, TokenValue::Str("print".to_string())),
+                (TokenType::LeftParenthesis, TokenValue::None),
+                (TokenType::Int, TokenValue::Int(1)),
+                (TokenType::RightParenthesis, TokenValue::None),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod string_escape_tests {
+    use super::*;
+
+    fn first_string(input: &str) -> (TokenValue, Option<LexErrorKind>) {
+        let mut tokenizer = tokenize(input);
+        let (token_type, value, _, error) = tokenizer.next().unwrap();
+
+        assert_eq!(token_type, TokenType::StringLiteral);
+        (value, error)
+    }
+
+    #[test]
+    fn hex_escape() {
+        assert_eq!(first_string("\"\\x41\\x42\""), (TokenValue::Bytes(b"AB".to_vec()), None));
+    }
+
+    #[test]
+    fn decimal_escape() {
+        assert_eq!(first_string("\"\\101\\066\""), (TokenValue::Bytes(b"eB".to_vec()), None));
+    }
+
+    #[test]
+    fn decimal_escape_above_255_is_invalid() {
+        let (value, error) = first_string("\"\\300\"");
+
+        assert_eq!(value, TokenValue::Bytes(Vec::new()));
+        assert_eq!(error, Some(LexErrorKind::InvalidEscape));
+    }
+
+    // `\xHH` and `\ddd` can decode to any byte 0-255, not just ASCII — the
+    // result isn't required to be valid UTF-8 on its own.
+    #[test]
+    fn hex_escape_above_ascii_range() {
+        assert_eq!(first_string("\"\\xFF\""), (TokenValue::Bytes(vec![0xFF]), None));
+    }
+
+    #[test]
+    fn decimal_escape_above_ascii_range() {
+        assert_eq!(first_string("\"\\200\""), (TokenValue::Bytes(vec![200]), None));
+    }
+
+    #[test]
+    fn unicode_escape() {
+        assert_eq!(first_string("\"\\u{1F600}\""), (TokenValue::Bytes("😀".as_bytes().to_vec()), None));
+    }
+
+    #[test]
+    fn z_escape_skips_following_whitespace() {
+        assert_eq!(first_string("\"a\\z\n   b\""), (TokenValue::Bytes(b"ab".to_vec()), None));
+    }
+
+    #[test]
+    fn invalid_escape_is_flagged_and_dropped() {
+        let (value, error) = first_string("\"\\q\"");
+
+        assert_eq!(value, TokenValue::Bytes(Vec::new()));
+        assert_eq!(error, Some(LexErrorKind::InvalidEscape));
+    }
+
+    #[test]
+    fn escape_followed_by_another_escape_is_not_swallowed() {
+        assert_eq!(
+            first_string("\"hi\\n\\t\\x41\\101\\u{1F600}end\""),
+            (TokenValue::Bytes("hi\n\tAe😀end".as_bytes().to_vec()), None)
+        );
+    }
+}
+
+#[cfg(test)]
+mod identifier_tests {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<(TokenType, TokenValue)> {
+        tokenize(input).map(|(t, v, _, _)| (t, v)).collect()
+    }
+
+    // Regression: `next_token` dispatches on `is_escape_char` before
+    // identifier scanning, and `is_escape_char` used to wrongly match the
+    // ASCII byte for 'A', so any identifier starting with 'A' lost its first
+    // character.
+    #[test]
+    fn identifier_starting_with_a_is_not_truncated() {
+        assert_eq!(
+            kinds("Apple"),
+            vec![
+                (TokenType::Identifier, TokenValue::Str("Apple".to_string())),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+
+    #[test]
+    fn ascii_identifier() {
+        assert_eq!(
+            kinds("my_var1"),
+            vec![
+                (TokenType::Identifier, TokenValue::Str("my_var1".to_string())),
+                (TokenType::Eof, TokenValue::None)
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifier_with_correct_column_count() {
+        let mut tokenizer = tokenize("café");
+        let (token_type, value, span, _) = tokenizer.next().unwrap();
+
+        assert_eq!(token_type, TokenType::Identifier);
+        assert_eq!(value, TokenValue::Str("café".to_string()));
+        // `end` is a byte offset: 'é' takes 2 bytes in UTF-8.
+        assert_eq!(span, Span { start: 0, end: 5, line: 1, col: 1 });
+
+        // The column counts characters, so the token after the 4-character
+        // identifier starts at column 5.
+        let (_, _, eof_span, _) = tokenizer.next().unwrap();
+        assert_eq!(eof_span.col, 5);
+    }
+}
+
+#[cfg(test)]
+mod span_tests {
+    use super::*;
+
+    #[test]
+    fn single_line_columns_advance_per_token() {
+        let spans: Vec<Span> = tokenize("ab cd").map(|(_, _, span, _)| span).collect();
+
+        assert_eq!(spans[0], Span { start: 0, end: 2, line: 1, col: 1 }); // "ab"
+        assert_eq!(spans[1], Span { start: 2, end: 3, line: 1, col: 3 }); // " "
+        assert_eq!(spans[2], Span { start: 3, end: 5, line: 1, col: 4 }); // "cd"
+    }
+
+    #[test]
+    fn newline_advances_line_and_resets_column() {
+        let spans: Vec<Span> = tokenize("a\nb").map(|(_, _, span, _)| span).collect();
+
+        assert_eq!(spans[0], Span { start: 0, end: 1, line: 1, col: 1 }); // "a"
+        assert_eq!(spans[1], Span { start: 1, end: 2, line: 1, col: 2 }); // "\n"
+        assert_eq!(spans[2], Span { start: 2, end: 3, line: 2, col: 1 }); // "b"
+    }
+
+    // A "\r\n" pair is one line break, not two: "\r" only advances the
+    // column, and the following "\n" is what bumps the line and resets it.
+    #[test]
+    fn crlf_counts_as_a_single_line_break() {
+        let spans: Vec<Span> = tokenize("a\r\nb").map(|(_, _, span, _)| span).collect();
+
+        assert_eq!(spans[0], Span { start: 0, end: 1, line: 1, col: 1 }); // "a"
+        assert_eq!(spans[3], Span { start: 3, end: 4, line: 2, col: 1 }); // "b"
+    }
+
+    #[test]
+    fn eof_span_is_empty_at_end_of_input() {
+        let mut tokenizer = tokenize("a");
+        tokenizer.next();
+        let (token_type, _, span, _) = tokenizer.next().unwrap();
+
+        assert_eq!(token_type, TokenType::Eof);
+        assert_eq!(span, Span { start: 1, end: 1, line: 1, col: 2 });
+    }
+}
+
+#[cfg(test)]
+mod iterator_tests {
+    use super::*;
+
+    #[test]
+    fn yields_eof_once_then_stops() {
+        let mut tokenizer = tokenize("a");
+
+        assert_eq!(tokenizer.next().unwrap().0, TokenType::Identifier);
+        assert_eq!(tokenizer.next().unwrap().0, TokenType::Eof);
+        assert_eq!(tokenizer.next(), None);
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn works_with_standard_iterator_adapters() {
+        let count = tokenize("a b c").filter(|(t, ..)| *t == TokenType::Identifier).count();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn for_loop_over_a_mutable_reference_still_exposes_the_tokenizer_afterwards() {
+        let mut tokenizer = tokenize("a");
+        let mut seen = Vec::new();
+
+        for token in &mut tokenizer {
+            seen.push(token.0);
+        }
+
+        assert_eq!(seen, vec![TokenType::Identifier, TokenType::Eof]);
+        assert_eq!(tokenizer.errors(), Vec::new());
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn unexpected_byte_is_flagged_without_panicking() {
+        let mut tokenizer = tokenize("@");
+        let (token_type, _, span, error) = tokenizer.next().unwrap();
+
+        assert_eq!(token_type, TokenType::Unidentified);
+        assert_eq!(error, Some(LexErrorKind::UnexpectedByte(b'@')));
+        assert_eq!(tokenizer.errors(), vec![(span, LexErrorKind::UnexpectedByte(b'@'))]);
+    }
+
+    #[test]
+    fn unexpected_byte_does_not_loop_forever() {
+        // Regression: the `Unidentified` fallback used to not advance past
+        // the bad byte, which combined with the Iterator's per-call `next`
+        // would have yielded the same token forever.
+        let tokens: Vec<_> = tokenize("@@").collect();
+        assert_eq!(tokens.len(), 3); // two `Unidentified` tokens, then Eof
+    }
+
+    #[test]
+    fn errors_accumulate_across_multiple_tokens() {
+        let mut tokenizer = tokenize("@ $");
+        let tokens: Vec<_> = (&mut tokenizer).collect();
+
+        assert_eq!(tokens[0].3, Some(LexErrorKind::UnexpectedByte(b'@')));
+        assert_eq!(tokens[2].3, Some(LexErrorKind::UnexpectedByte(b'$')));
+        assert_eq!(tokenizer.errors().len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod operator_tests {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<TokenType> {
+        tokenize(input).map(|(t, ..)| t).collect()
+    }
+
+    // Regression: `==` used to be mis-tagged as `Concat` instead of `Equal`.
+    #[test]
+    fn double_equals_is_equal_not_concat() {
+        assert_eq!(kinds("a==b"), vec![
+            TokenType::Identifier,
+            TokenType::Equal,
+            TokenType::Identifier,
+            TokenType::Eof
+        ]);
+    }
+
+    #[test]
+    fn single_equals_is_assign() {
+        assert_eq!(kinds("a=b"), vec![
+            TokenType::Identifier,
+            TokenType::Assign,
+            TokenType::Identifier,
+            TokenType::Eof
+        ]);
+    }
+
+    #[test]
+    fn double_slash_is_idiv_not_two_divs() {
+        assert_eq!(kinds("a//b"), vec![
+            TokenType::Identifier,
+            TokenType::IDiv,
+            TokenType::Identifier,
+            TokenType::Eof
+        ]);
+    }
+
+    #[test]
+    fn single_slash_is_div() {
+        assert_eq!(kinds("a/b"), vec![
+            TokenType::Identifier,
+            TokenType::Div,
+            TokenType::Identifier,
+            TokenType::Eof
+        ]);
+    }
+}
\ No newline at end of file